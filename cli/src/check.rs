@@ -0,0 +1,48 @@
+//! Hash-based post-write verification for `--check`.
+//!
+//! Computes the source image's digest once (streaming the decompressed
+//! bytes through a `Digest`), then for each written disk hashes back the
+//! first `image_size` bytes through the same `Digest` and compares the
+//! hex. This replaces re-streaming the whole image once per disk with a
+//! single read per side of the comparison.
+
+use crate::decompress;
+use crate::hash::{hasher_prefix, hasher_reader};
+use async_std::path::{Path, PathBuf};
+use popsicle::Progress;
+use sha2::Sha256;
+
+/// Hashes the full decompressed image stream at `image_path`.
+pub async fn source_digest(image_path: &Path) -> anyhow::Result<String> {
+    let decompress::OpenedImage { reader, .. } = decompress::open(image_path).await?;
+    hasher_reader::<Sha256, _>(reader).await
+}
+
+/// Hashes the first `image_size` bytes of each disk in `disk_paths` and
+/// reports a `Verified`/`Mismatch` message through `progress` for each.
+///
+/// Returns `true` if any disk's digest differed from `expected`.
+pub async fn verify_disks(
+    disk_paths: &[PathBuf],
+    image_size: u64,
+    expected: &str,
+    progress: &mut dyn Progress,
+) -> bool {
+    let mut mismatched = false;
+
+    for path in disk_paths {
+        match hasher_prefix::<Sha256>(path, image_size).await {
+            Ok(found) if found == expected => progress.message(path, "Verified", ""),
+            Ok(found) => {
+                mismatched = true;
+                progress.message(path, "Mismatch", &format!("expected {}, found {}", expected, found));
+            }
+            Err(why) => {
+                mismatched = true;
+                progress.message(path, "Mismatch", &why.to_string());
+            }
+        }
+    }
+
+    mismatched
+}
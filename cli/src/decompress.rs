@@ -0,0 +1,181 @@
+//! Sniffs the compression format of an image and wraps it in a decoding
+//! reader so that `popsicle()` can stream decompressed bytes straight into
+//! `Task::process`, without first unpacking the image to a scratch file.
+
+use anyhow::Context;
+use async_compression::async_std::bufread::{BzDecoder, GzipDecoder, XzDecoder, ZstdDecoder};
+use async_std::{
+    fs::File,
+    io::{prelude::*, BufReader, SeekFrom},
+    path::Path,
+};
+use std::pin::Pin;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const XZ_MAGIC: [u8; 6] = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: [u8; 3] = [0x42, 0x5a, 0x68];
+
+/// The compression format detected from an image's leading bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+    Raw,
+}
+
+impl Format {
+    fn sniff(header: &[u8]) -> Format {
+        if header.starts_with(&GZIP_MAGIC) {
+            Format::Gzip
+        } else if header.starts_with(&XZ_MAGIC) {
+            Format::Xz
+        } else if header.starts_with(&ZSTD_MAGIC) {
+            Format::Zstd
+        } else if header.starts_with(&BZIP2_MAGIC) {
+            Format::Bzip2
+        } else {
+            Format::Raw
+        }
+    }
+}
+
+/// An image opened for reading, along with its uncompressed length when it
+/// could be determined cheaply (i.e.: without decoding the whole stream).
+pub struct OpenedImage {
+    pub reader: Pin<Box<dyn Read + Send>>,
+    pub size: Option<u64>,
+}
+
+/// Opens `path`, sniffs its leading bytes for a known compression magic, and
+/// returns a single forward-only stream of decompressed bytes.
+///
+/// The returned stream is meant to be read once, in order, the same way the
+/// per-disk write loop consumes a raw image: no seeking back onto it.
+pub async fn open(path: &Path) -> anyhow::Result<OpenedImage> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("error with image at '{}'", path.display()))?;
+
+    let mut header = [0u8; 6];
+    let read = read_full(&mut file, &mut header).await?;
+    file.seek(SeekFrom::Start(0))
+        .await
+        .with_context(|| format!("failed to seek image at '{}'", path.display()))?;
+
+    let format = Format::sniff(&header[..read]);
+    let size = uncompressed_size(&mut file, format)
+        .await
+        .with_context(|| format!("failed to read compressed image at '{}'", path.display()))?;
+
+    file.seek(SeekFrom::Start(0))
+        .await
+        .with_context(|| format!("failed to seek image at '{}'", path.display()))?;
+
+    let reader = BufReader::new(file);
+
+    let reader: Pin<Box<dyn Read + Send>> = match format {
+        Format::Gzip => Box::pin(GzipDecoder::new(reader)),
+        Format::Xz => Box::pin(XzDecoder::new(reader)),
+        Format::Zstd => Box::pin(ZstdDecoder::new(reader)),
+        Format::Bzip2 => Box::pin(BzDecoder::new(reader)),
+        Format::Raw => Box::pin(reader),
+    };
+
+    Ok(OpenedImage { reader, size })
+}
+
+async fn read_full(file: &mut File, buf: &mut [u8]) -> anyhow::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let read = file.read(&mut buf[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+/// Derives the uncompressed length of the image where that is cheap to do,
+/// without decoding the whole stream. Returns `None` when the format does
+/// not expose its length up front (the progress bar then falls back to an
+/// indeterminate spinner).
+async fn uncompressed_size(file: &mut File, format: Format) -> anyhow::Result<Option<u64>> {
+    match format {
+        Format::Gzip => {
+            let len = file.metadata().await?.len();
+            if len < 8 {
+                return Ok(None);
+            }
+
+            // The last 4 bytes of a gzip stream are the little-endian
+            // uncompressed size, modulo 2^32.
+            file.seek(SeekFrom::End(-4)).await?;
+            let mut isize_bytes = [0u8; 4];
+            file.read_exact(&mut isize_bytes).await?;
+
+            Ok(Some(u32::from_le_bytes(isize_bytes) as u64))
+        }
+        Format::Zstd => {
+            // Frame_Header_Descriptor lives right after the 4-byte magic;
+            // its top two bits give the width of the following
+            // Content_Size field (0, 1, 2, or 3 -> 0, 2, 4, or 8 bytes;
+            // 0 means 1 byte instead when the Single_Segment bit is set).
+            file.seek(SeekFrom::Start(4)).await?;
+            let mut descriptor = [0u8; 1];
+            if file.read(&mut descriptor).await? == 0 {
+                return Ok(None);
+            }
+
+            let fcs_field_size = match descriptor[0] >> 6 {
+                0 if descriptor[0] & 0x20 != 0 => 1,
+                0 => 0,
+                1 => 2,
+                2 => 4,
+                _ => 8,
+            };
+
+            if fcs_field_size == 0 {
+                return Ok(None);
+            }
+
+            let single_segment = descriptor[0] & 0x20 != 0;
+            if !single_segment {
+                // Window_Descriptor precedes Dictionary_ID/Content_Size
+                // when the frame isn't single-segment.
+                file.seek(SeekFrom::Current(1)).await?;
+            }
+
+            // Dictionary_ID, when present, sits between Window_Descriptor
+            // and Content_Size; its width is encoded in the descriptor's
+            // bottom two bits (0, 1, 2, or 3 -> 0, 1, 2, or 4 bytes).
+            let did_field_size: i64 = match descriptor[0] & 0x03 {
+                0 => 0,
+                1 => 1,
+                2 => 2,
+                _ => 4,
+            };
+            if did_field_size > 0 {
+                file.seek(SeekFrom::Current(did_field_size)).await?;
+            }
+
+            let mut fcs = [0u8; 8];
+            file.read_exact(&mut fcs[..fcs_field_size]).await?;
+            let mut value = u64::from_le_bytes(fcs);
+            if fcs_field_size == 2 {
+                value += 256;
+            }
+
+            Ok(Some(value))
+        }
+        // An uncompressed image's length is just its file size.
+        Format::Raw => Ok(Some(file.metadata().await?.len())),
+        // The xz index carries the uncompressed size, but only after
+        // scanning backward through block headers; not cheap enough to
+        // bother with here. bzip2 never records it at all.
+        Format::Xz | Format::Bzip2 => Ok(None),
+    }
+}
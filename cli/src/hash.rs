@@ -0,0 +1,50 @@
+//! Streams a file (or the first few bytes of one) through a `Digest`
+//! without holding it all in memory.
+
+use anyhow::Context;
+use async_std::{fs::File, io::prelude::*, path::Path};
+use digest::Digest;
+use hex_view::HexView;
+
+pub async fn hasher<H: Digest>(path: &Path) -> anyhow::Result<String> {
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("error opening '{}' for hashing", path.display()))?;
+
+    hash::<H, _>(file, u64::MAX).await
+}
+
+/// Hashes an already-open reader to completion, e.g. a decompressing
+/// stream that has no path of its own to open.
+pub async fn hasher_reader<H: Digest, R: Read + Unpin>(reader: R) -> anyhow::Result<String> {
+    hash::<H, _>(reader, u64::MAX).await
+}
+
+/// Hashes only the first `limit` bytes of `path`, stopping early once that
+/// many bytes have been read. Used to check a written disk against a
+/// source image's digest without reading past the image's own length.
+pub async fn hasher_prefix<H: Digest>(path: &Path, limit: u64) -> anyhow::Result<String> {
+    let file = File::open(path)
+        .await
+        .with_context(|| format!("error opening '{}' for hashing", path.display()))?;
+
+    hash::<H, _>(file, limit).await
+}
+
+async fn hash<H: Digest, R: Read + Unpin>(mut reader: R, limit: u64) -> anyhow::Result<String> {
+    let mut buffer = [0u8; 8 * 1024];
+    let mut hasher = H::new();
+    let mut remaining = limit;
+
+    while remaining > 0 {
+        let want = (buffer.len() as u64).min(remaining) as usize;
+        let read = reader.read(&mut buffer[..want]).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        remaining -= read as u64;
+    }
+
+    Ok(format!("{:x}", HexView::from(hasher.finalize().as_slice())))
+}
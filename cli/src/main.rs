@@ -9,12 +9,15 @@ extern crate derive_new;
 #[macro_use]
 extern crate fomat_macros;
 
+mod check;
+mod decompress;
+mod hash;
+mod udisks;
+mod verify;
+mod watch;
+
 use anyhow::Context;
-use async_std::{
-    fs::OpenOptions,
-    os::unix::fs::OpenOptionsExt,
-    path::{Path, PathBuf},
-};
+use async_std::path::{Path, PathBuf};
 use clap::{App, Arg, ArgMatches};
 use futures::{
     channel::{mpsc, oneshot},
@@ -37,6 +40,11 @@ fn main() {
         .arg(Arg::with_name("IMAGE").help("Input image file").required(true))
         .arg(Arg::with_name("DISKS").help("Output disk devices").multiple(true))
         .arg(Arg::with_name("all").help("Flash all detected USB drives").short("a").long("all"))
+        .arg(
+            Arg::with_name("watch")
+                .help("Keep watching for USB drives to arrive while confirming (requires --all)")
+                .long("watch"),
+        )
         .arg(
             Arg::with_name("check")
                 .help("Check written image matches read image")
@@ -44,7 +52,26 @@ fn main() {
                 .long("check"),
         )
         .arg(Arg::with_name("unmount").help("Unmount mounted devices").short("u").long("unmount"))
+        .arg(
+            Arg::with_name("eject")
+                .help("Power off each drive through udisks2 after a successful write")
+                .long("eject"),
+        )
         .arg(Arg::with_name("yes").help("Continue without confirmation").short("y").long("yes"))
+        .arg(
+            Arg::with_name("verify")
+                .help("Verify the image against a hex digest before flashing")
+                .long("verify")
+                .takes_value(true)
+                .value_name("HASH"),
+        )
+        .arg(
+            Arg::with_name("algo")
+                .help("Hash algorithm to use with --verify (md5, sha1, sha256)")
+                .long("algo")
+                .takes_value(true)
+                .value_name("ALGO"),
+        )
         .get_matches();
 
     let (rtx, rrx) = oneshot::channel::<anyhow::Result<()>>();
@@ -75,18 +102,23 @@ async fn popsicle(
 ) -> anyhow::Result<()> {
     let image_path = matches.value_of("IMAGE").expect("IMAGE not set");
 
-    let image = OpenOptions::new()
-        .custom_flags(libc::O_SYNC)
-        .read(true)
-        .open(image_path)
+    verify::verify(image_path, matches.value_of("verify"), matches.value_of("algo"))
         .await
-        .with_context(|| format!("error with image at '{}'", image_path))?;
+        .with_context(|| format!("image at '{}' failed verification", image_path))?;
 
-    let image_size = image
-        .metadata()
-        .await
-        .map(|x| x.len())
-        .with_context(|| format!("image metadata error at '{}'", image_path))?;
+    let decompress::OpenedImage {
+        reader: image,
+        size,
+    } = decompress::open(Path::new(image_path)).await?;
+
+    // Compressed images report their uncompressed length only when that is
+    // cheap to determine (see `decompress::open`); otherwise fall back to
+    // an indeterminate bar.
+    let image_size = size.unwrap_or(0);
+
+    if matches.is_present("watch") && !matches.is_present("all") {
+        return Err(anyhow!("--watch requires --all"));
+    }
 
     let mut disk_args = Vec::new();
     if matches.is_present("all") {
@@ -95,8 +127,39 @@ async fn popsicle(
         disk_args.extend(disks.map(String::from).map(PathBuf::from).map(Box::from));
     }
 
-    if disk_args.is_empty() {
-        return Err(anyhow!("no disks specified"));
+    let is_tty = atty::is(atty::Stream::Stdout);
+    let confirm_required = is_tty && !matches.is_present("yes");
+
+    let disk_args = if matches.is_present("watch") && confirm_required {
+        watch_for_disks(image_path, disk_args).await?
+    } else {
+        if disk_args.is_empty() {
+            return Err(anyhow!("no disks specified"));
+        }
+
+        if confirm_required {
+            confirm(image_path, &disk_args)?;
+        }
+
+        disk_args
+    };
+
+    if matches.is_present("unmount") {
+        let mut progress = StepProgress::new(is_tty);
+
+        for path in &disk_args {
+            // A udisks2-managed filesystem may not be visible under `/` at
+            // all (or may auto-mount again mid-flash), so tear it down
+            // through udisks2 rather than only consulting `/proc/mounts`.
+            match udisks::unmount(path) {
+                Ok(errors) => {
+                    for why in errors {
+                        progress.message(path, "Unmount", &why.to_string());
+                    }
+                }
+                Err(why) => progress.message(path, "Unmount", &why.to_string()),
+            }
+        }
     }
 
     let mounts = mnt::get_submounts(Path::new("/")).context("error reading mounts")?;
@@ -106,35 +169,29 @@ async fn popsicle(
             .await
             .context("failed to open disks")?;
 
-    let is_tty = atty::is(atty::Stream::Stdout);
-
-    if is_tty && !matches.is_present("yes") {
-        epint!(
-            "Are you sure you want to flash '" (image_path) "' to the following drives?\n"
-            for (path, _) in &disks {
-                " - " (path.display()) "\n"
-            }
-            "y/N: "
-        );
-
-        io::stdout().flush().unwrap();
-
-        let mut confirm = String::new();
-        io::stdin().read_line(&mut confirm).unwrap();
-
-        if confirm.trim() != "y" && confirm.trim() != "yes" {
-            return Err(anyhow!("exiting without flashing"));
-        }
-    }
+    let eject = matches.is_present("eject");
+    let disk_paths: Vec<PathBuf> = disks.iter().map(|(path, _)| path.to_path_buf()).collect();
 
+    // Verification is now done ourselves below, by hashing each disk's
+    // first `image_size` bytes against a digest of the source image taken
+    // once up front, rather than Task re-streaming the whole image once
+    // per disk -- so Task is never asked to check on its own.
     let check = matches.is_present("check");
+    let source_digest = match (check, image_size) {
+        (true, 0) => {
+            eprintln!("popsicle: warning: image size is unknown, skipping --check");
+            None
+        }
+        (true, _) => Some(check::source_digest(Path::new(image_path)).await?),
+        (false, _) => None,
+    };
 
     // If this is a TTY, display a progress bar. If not, display machine-readable info.
     if is_tty {
         println!();
 
         let mut mb = MultiBar::new();
-        let mut task = Task::new(image, check);
+        let mut task = Task::new(image, false);
 
         for (disk_path, disk) in disks {
             let pb = InteractiveProgress::new(cascade! {
@@ -154,10 +211,26 @@ async fn popsicle(
         });
 
         mb.listen();
+
+        let mismatched = match &source_digest {
+            Some(expected) => {
+                let mut progress = StepProgress::new(is_tty);
+                check::verify_disks(&disk_paths, image_size, expected, &mut progress).await
+            }
+            None => false,
+        };
+
+        if eject {
+            eject_disks(&disk_paths, &mut StepProgress::new(is_tty));
+        }
+
+        if mismatched {
+            return Err(anyhow!("one or more devices failed checksum verification"));
+        }
     } else {
         let (etx, erx) = mpsc::unbounded();
         let mut paths = Vec::new();
-        let mut task = Task::new(image, check);
+        let mut task = Task::new(image, false);
 
         for (disk_path, disk) in disks {
             let pb = MachineProgress::new(paths.len(), etx.clone());
@@ -173,11 +246,158 @@ async fn popsicle(
         };
 
         join!(machine_output(erx, &paths, image_size), task);
+
+        let mismatched = match &source_digest {
+            Some(expected) => {
+                let mut progress = StepProgress::new(is_tty);
+                check::verify_disks(&disk_paths, image_size, expected, &mut progress).await
+            }
+            None => false,
+        };
+
+        if eject {
+            eject_disks(&disk_paths, &mut StepProgress::new(is_tty));
+        }
+
+        if mismatched {
+            return Err(anyhow!("one or more devices failed checksum verification"));
+        }
     }
 
     Ok(())
 }
 
+/// Powers off each drive through udisks2 so it is safe to unplug, once
+/// flashing has finished. Best-effort: a drive that fails to eject is
+/// reported but does not fail the overall run.
+fn eject_disks(disk_paths: &[PathBuf], progress: &mut dyn Progress) {
+    for path in disk_paths {
+        if let Err(why) = udisks::eject(path) {
+            progress.message(path, "Eject", &why.to_string());
+        }
+    }
+}
+
+/// A one-off `Progress` for status lines that happen outside the per-disk
+/// write loop (unmounting before a flash, ejecting after one), where no
+/// `InteractiveProgress`/`MachineProgress` exists yet to report through.
+/// Reports in whichever of the two formats the rest of the run is using,
+/// so a `machine_output` consumer sees these steps too instead of only
+/// ever getting them on stderr.
+struct StepProgress {
+    machine: bool,
+}
+
+impl StepProgress {
+    fn new(is_tty: bool) -> StepProgress {
+        StepProgress { machine: !is_tty }
+    }
+}
+
+impl Progress for StepProgress {
+    fn message(&mut self, path: &Path, kind: &str, message: &str) {
+        if self.machine {
+            let stdout = io::stdout();
+            let stdout = &mut stdout.lock();
+            match kind {
+                "Verified" => {
+                    let _ = witeln!(stdout, "Verified(\"" (path.display()) "\")");
+                }
+                "Mismatch" => {
+                    let _ = witeln!(stdout, "Mismatch(\"" (path.display()) "\",\"" (message) "\")");
+                }
+                _ => {
+                    let _ = witeln!(stdout, "Message(\"" (path.display()) "\",\"" (kind) " " (message) "\")");
+                }
+            }
+        } else {
+            eprintln!("{} {}: {}", kind, path.display(), message);
+        }
+    }
+
+    fn finish(&mut self) {}
+
+    fn set(&mut self, _written: u64) {}
+}
+
+/// Prompts once for confirmation against a fixed list of drives.
+fn confirm(image_path: &str, disk_args: &[Box<Path>]) -> anyhow::Result<()> {
+    print_candidates(image_path, disk_args);
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap();
+
+    if line.trim() == "y" || line.trim() == "yes" {
+        Ok(())
+    } else {
+        Err(anyhow!("exiting without flashing"))
+    }
+}
+
+fn print_candidates(image_path: &str, disk_args: &[Box<Path>]) {
+    epint!(
+        "Are you sure you want to flash '" (image_path) "' to the following drives?\n"
+        for path in disk_args {
+            " - " (path.display()) "\n"
+        }
+        "y/N: "
+    );
+
+    io::stdout().flush().unwrap();
+}
+
+/// Keeps the confirmation prompt open while re-scanning attached USB drives
+/// as they arrive or are removed, so a stick plugged in after launch still
+/// becomes a candidate without restarting.
+async fn watch_for_disks(
+    image_path: &str,
+    mut disk_args: Vec<Box<Path>>,
+) -> anyhow::Result<Vec<Box<Path>>> {
+    let (tick_tx, tick_rx) = mpsc::unbounded::<()>();
+    thread::spawn(move || {
+        let watcher = match watch::BlockWatcher::new() {
+            Ok(watcher) => watcher,
+            Err(_) => return,
+        };
+
+        while watcher.wait() {
+            if tick_tx.unbounded_send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let (confirm_tx, confirm_rx) = oneshot::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let _ = io::stdin().read_line(&mut line);
+        let _ = confirm_tx.send(line);
+    });
+
+    print_candidates(image_path, &disk_args);
+
+    let mut tick_rx = tick_rx.fuse();
+    let mut confirm_rx = confirm_rx.fuse();
+
+    loop {
+        futures::select! {
+            line = confirm_rx => {
+                return match line {
+                    Ok(line) if line.trim() == "y" || line.trim() == "yes" => Ok(disk_args),
+                    _ => Err(anyhow!("exiting without flashing")),
+                };
+            }
+            _ = tick_rx.next() => {
+                disk_args.clear();
+                popsicle::usb_disk_devices(&mut disk_args)
+                    .await
+                    .context("error getting USB disks")?;
+                print_candidates(image_path, &disk_args);
+            }
+        }
+    }
+}
+
 /// An event for creating a machine-readable output
 pub enum Event {
     Message(usize, Box<str>),
@@ -230,11 +450,7 @@ impl Progress for InteractiveProgress {
 }
 
 /// Writes a machine-friendly output, when this program is being piped into another.
-async fn machine_output(
-    mut rx: mpsc::UnboundedReceiver<Event>,
-    paths: &[Box<Path>],
-    image_size: u64,
-) {
+async fn machine_output(mut rx: mpsc::UnboundedReceiver<Event>, paths: &[Box<Path>], image_size: u64) {
     let stdout = io::stdout();
     let stdout = &mut stdout.lock();
 
@@ -0,0 +1,169 @@
+//! Unmounts and ejects target drives through udisks2 instead of assuming a
+//! filesystem is only ever mounted where `/proc/mounts` says it is. The
+//! GTK side already talks to udisks2 via `dbus_udisks2::DiskDevice`; this
+//! gives the CLI the same guarantee that a partition which auto-mounts
+//! mid-flash still gets torn down cleanly.
+
+use anyhow::{anyhow, Context};
+use async_std::path::Path;
+use dbus::{
+    arg::{RefArg, Variant},
+    blocking::Connection,
+};
+use std::{collections::HashMap, time::Duration};
+
+const SERVICE: &str = "org.freedesktop.UDisks2";
+const MANAGER_PATH: &str = "/org/freedesktop/UDisks2";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+type ManagedObjects =
+    HashMap<dbus::Path<'static>, HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>>;
+
+/// Unmounts every currently-mounted filesystem found on `device`'s
+/// partitions through udisks2, regardless of whether the mount happens to
+/// be visible under `/`.
+///
+/// A partition failing to unmount does not stop the rest from being tried:
+/// callers get back every error encountered so they can report them all,
+/// rather than a single partition aborting the whole teardown.
+pub fn unmount(device: &Path) -> anyhow::Result<Vec<anyhow::Error>> {
+    let mut errors = Vec::new();
+
+    for fs_path in mounted_filesystems_of(device)? {
+        let connection = connect()?;
+        let proxy = connection.with_proxy(SERVICE, &fs_path, TIMEOUT);
+        let options: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+
+        if let Err(why) = proxy
+            .method_call("org.freedesktop.UDisks2.Filesystem", "Unmount", (options,))
+            .with_context(|| format!("failed to unmount '{}'", fs_path))
+        {
+            errors.push(why);
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Powers off (ejects) the drive backing `device` so it is safe to pull
+/// once flashing has finished.
+pub fn eject(device: &Path) -> anyhow::Result<()> {
+    let drive_path = drive_of(device)?;
+
+    let connection = connect()?;
+    let proxy = connection.with_proxy(SERVICE, &drive_path, TIMEOUT);
+    let options: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+
+    proxy
+        .method_call("org.freedesktop.UDisks2.Drive", "PowerOff", (options,))
+        .with_context(|| format!("failed to power off '{}'", drive_path))?;
+
+    Ok(())
+}
+
+fn connect() -> anyhow::Result<Connection> {
+    Connection::new_system().context("error connecting to the system bus")
+}
+
+fn managed_objects(connection: &Connection) -> anyhow::Result<ManagedObjects> {
+    let proxy = connection.with_proxy(SERVICE, MANAGER_PATH, TIMEOUT);
+    let (objects,): (ManagedObjects,) = proxy
+        .method_call(
+            "org.freedesktop.DBus.ObjectManager",
+            "GetManagedObjects",
+            (),
+        )
+        .context("error enumerating udisks2 objects")?;
+
+    Ok(objects)
+}
+
+/// Finds every currently-mounted `org.freedesktop.UDisks2.Filesystem`
+/// object on a partition of the same drive as `device`, so a mount on
+/// `/dev/sdb1` is found when `device` is `/dev/sdb`.
+fn mounted_filesystems_of(device: &Path) -> anyhow::Result<Vec<dbus::Path<'static>>> {
+    let drive_path = drive_of(device)?;
+
+    let connection = connect()?;
+    let objects = managed_objects(&connection)?;
+
+    let paths = objects
+        .iter()
+        .filter(|(_, interfaces)| same_drive(interfaces, &drive_path))
+        .filter(|(_, interfaces)| !mount_points_empty(interfaces))
+        .map(|(path, _)| path.clone())
+        .collect();
+
+    Ok(paths)
+}
+
+/// Whether `interfaces` belongs to a `Filesystem` object whose
+/// `org.freedesktop.UDisks2.Block` sibling reports `drive_path` as its
+/// drive.
+fn same_drive(
+    interfaces: &HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>,
+    drive_path: &dbus::Path<'static>,
+) -> bool {
+    interfaces.contains_key("org.freedesktop.UDisks2.Filesystem")
+        && interfaces
+            .get("org.freedesktop.UDisks2.Block")
+            .and_then(|props| props.get("Drive"))
+            .and_then(|drive| drive.0.as_str())
+            .map(|drive| drive == &**drive_path)
+            .unwrap_or(false)
+}
+
+/// Whether a `Filesystem` object's `MountPoints` property is empty, i.e.
+/// the filesystem exists but isn't currently mounted anywhere.
+fn mount_points_empty(
+    interfaces: &HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>,
+) -> bool {
+    interfaces
+        .get("org.freedesktop.UDisks2.Filesystem")
+        .and_then(|props| props.get("MountPoints"))
+        .and_then(|value| value.0.as_any().downcast_ref::<Vec<Vec<u8>>>())
+        .map(|points| points.is_empty())
+        .unwrap_or(true)
+}
+
+/// Finds the `org.freedesktop.UDisks2.Drive` object backing `device`.
+fn drive_of(device: &Path) -> anyhow::Result<dbus::Path<'static>> {
+    let connection = connect()?;
+    let objects = managed_objects(&connection)?;
+
+    let block = objects
+        .iter()
+        .find(|(_, interfaces)| {
+            interfaces.contains_key("org.freedesktop.UDisks2.Block")
+                && block_device_matches(interfaces, device)
+        })
+        .with_context(|| format!("no udisks2 block device found for '{}'", device.display()))?;
+
+    let drive: &str = block
+        .1
+        .get("org.freedesktop.UDisks2.Block")
+        .and_then(|props| props.get("Drive"))
+        .and_then(|drive| drive.0.as_str())
+        .with_context(|| format!("'{}' has no associated drive", device.display()))?;
+
+    if drive == "/" {
+        return Err(anyhow!("'{}' has no associated drive", device.display()));
+    }
+
+    Ok(dbus::Path::new(drive.to_owned()).expect("udisks2 returned an invalid object path"))
+}
+
+fn block_device_matches(
+    interfaces: &HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>,
+    device: &Path,
+) -> bool {
+    interfaces
+        .get("org.freedesktop.UDisks2.Block")
+        .and_then(|props| props.get("Device"))
+        .and_then(|value| value.0.as_any().downcast_ref::<Vec<u8>>())
+        .map(|bytes| {
+            let path = String::from_utf8_lossy(bytes);
+            Path::new(path.trim_end_matches('\0')) == device
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,137 @@
+//! Verifies the source image against an expected digest before any disk is
+//! opened, so a truncated or corrupted download is never written to
+//! multiple drives before anyone notices.
+
+use crate::hash::hasher;
+use anyhow::Context;
+use async_std::path::{Path, PathBuf};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::Sha256;
+
+/// A digest algorithm usable for pre-flash verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl Algorithm {
+    /// Chooses an algorithm from a hex digest's length: 32 / 40 / 64 hex
+    /// characters identify MD5 / SHA1 / SHA256 respectively.
+    fn from_digest_len(len: usize) -> anyhow::Result<Algorithm> {
+        match len {
+            32 => Ok(Algorithm::Md5),
+            40 => Ok(Algorithm::Sha1),
+            64 => Ok(Algorithm::Sha256),
+            other => Err(anyhow!(
+                "cannot infer hash algorithm from a {}-character digest",
+                other
+            )),
+        }
+    }
+
+    fn from_name(name: &str) -> anyhow::Result<Algorithm> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Ok(Algorithm::Md5),
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            other => Err(anyhow!("unsupported hash algorithm '{}'", other)),
+        }
+    }
+
+    fn sidecar_extension(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    async fn hash(self, image: &Path) -> anyhow::Result<String> {
+        match self {
+            Algorithm::Md5 => hasher::<Md5>(image).await,
+            Algorithm::Sha1 => hasher::<Sha1>(image).await,
+            Algorithm::Sha256 => hasher::<Sha256>(image).await,
+        }
+    }
+}
+
+/// The digest a caller expects the image to produce (from an explicit flag
+/// or a sidecar file next to the image), and the algorithm to compute it
+/// with.
+struct Expected {
+    algorithm: Algorithm,
+    digest: String,
+}
+
+/// Verifies `image` against an expected digest supplied via `--verify`
+/// (with an optional `--algo` override), or auto-detected from a
+/// `IMAGE.sha256` / `IMAGE.md5` / `IMAGE.sha1` sidecar file.
+///
+/// Does nothing when neither an explicit digest nor a sidecar file exists.
+pub async fn verify(
+    image_path: &str,
+    verify: Option<&str>,
+    algo: Option<&str>,
+) -> anyhow::Result<()> {
+    let expected = match verify {
+        Some(digest) => {
+            let digest = digest.trim().to_ascii_lowercase();
+            let algorithm = match algo {
+                Some(name) => Algorithm::from_name(name)?,
+                None => Algorithm::from_digest_len(digest.len())?,
+            };
+            Some(Expected { algorithm, digest })
+        }
+        None => sidecar(image_path).await?,
+    };
+
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+
+    let found = expected
+        .algorithm
+        .hash(Path::new(image_path))
+        .await
+        .with_context(|| format!("error hashing image at '{}'", image_path))?;
+
+    if found != expected.digest {
+        return Err(anyhow!(
+            "checksum mismatch for '{}': expected {}, found {}",
+            image_path,
+            expected.digest,
+            found
+        ));
+    }
+
+    Ok(())
+}
+
+/// Looks for a sidecar file of the form `IMAGE.sha256`, `IMAGE.md5`, or
+/// `IMAGE.sha1` next to the image, preferring the strongest algorithm.
+async fn sidecar(image_path: &str) -> anyhow::Result<Option<Expected>> {
+    for algorithm in [Algorithm::Sha256, Algorithm::Sha1, Algorithm::Md5] {
+        let path = PathBuf::from(format!("{}.{}", image_path, algorithm.sidecar_extension()));
+        if !path.exists().await {
+            continue;
+        }
+
+        let contents = async_std::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("error reading sidecar '{}'", path.display()))?;
+
+        let digest = contents
+            .split_whitespace()
+            .next()
+            .with_context(|| format!("sidecar '{}' is empty", path.display()))?
+            .to_ascii_lowercase();
+
+        return Ok(Some(Expected { algorithm, digest }));
+    }
+
+    Ok(None)
+}
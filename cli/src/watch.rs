@@ -0,0 +1,46 @@
+//! Watches `/sys/class/block` for drive arrival/removal so `--watch` mode
+//! can keep the confirmation prompt open while a stick is plugged in,
+//! instead of requiring a restart to see it.
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{path::Path, sync::mpsc, time::Duration};
+
+/// How long to wait for more sysfs events before treating a hotplug burst
+/// as settled; udev tends to fire several events per physical insertion.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+pub struct BlockWatcher {
+    // Kept alive for as long as the watcher is needed; dropping it stops
+    // the underlying inotify instance.
+    _watcher: RecommendedWatcher,
+    rx: mpsc::Receiver<()>,
+}
+
+impl BlockWatcher {
+    pub fn new() -> anyhow::Result<BlockWatcher> {
+        let (tx, rx) = mpsc::channel();
+
+        let mut watcher: RecommendedWatcher =
+            Watcher::new_immediate(move |_event| drop(tx.send(())))?;
+
+        watcher.watch(Path::new("/sys/class/block"), RecursiveMode::NonRecursive)?;
+
+        Ok(BlockWatcher {
+            _watcher: watcher,
+            rx,
+        })
+    }
+
+    /// Blocks until a device arrives or is removed, coalescing a burst of
+    /// sysfs events into a single tick. Returns `false` once the watcher
+    /// has been dropped.
+    pub fn wait(&self) -> bool {
+        if self.rx.recv().is_err() {
+            return false;
+        }
+
+        while self.rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+        true
+    }
+}
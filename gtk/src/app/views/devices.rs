@@ -1,9 +1,12 @@
-use crate::misc;
 use super::View;
+use crate::misc;
+use crate::watch::watch_block_devices;
 use dbus_udisks2::DiskDevice;
 use gtk;
 use gtk::prelude::*;
+use notify::RecommendedWatcher;
 use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -16,6 +19,13 @@ pub struct DevicesView {
     pub list: gtk::ListBox,
     pub select_all: gtk::CheckButton,
     view_ready: ViewReadySignal,
+    devices: RefCell<Vec<Arc<DiskDevice>>>,
+}
+
+/// The block device node that identifies a disk across refreshes, used to
+/// carry a selection forward when the candidate list changes underneath it.
+fn device_path(device: &DiskDevice) -> Option<&PathBuf> {
+    device.parent.devices.first()
 }
 
 impl DevicesView {
@@ -70,7 +80,13 @@ impl DevicesView {
 
         let view_ready: ViewReadySignal = Rc::new(RefCell::new(Box::new(|_| ())));
 
-        DevicesView { view, list, select_all, view_ready }
+        DevicesView {
+            view,
+            list,
+            select_all,
+            view_ready,
+            devices: RefCell::new(Vec::new()),
+        }
     }
 
     pub fn get_buttons(&self) -> impl Iterator<Item = gtk::CheckButton> {
@@ -89,12 +105,23 @@ impl DevicesView {
     }
 
     pub fn refresh(&self, devices: &[Arc<DiskDevice>], image_size: u64) {
+        let active_ids: std::collections::HashSet<usize> = self.get_active_ids().collect();
+        let selected: Vec<PathBuf> = self
+            .devices
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| active_ids.contains(id))
+            .filter_map(|(_, device)| device_path(device).cloned())
+            .collect();
+
         self.list.foreach(|w| self.list.remove(w));
 
         let nselected = Rc::new(Cell::new(0));
 
         for device in devices {
             let valid_size = device.parent.size >= image_size;
+            let was_selected = device_path(device).map_or(false, |path| selected.contains(path));
 
             let label = &misc::device_label(&device);
 
@@ -111,6 +138,7 @@ impl DevicesView {
             let row = cascade! {
                 gtk::CheckButton::new();
                 ..set_sensitive(valid_size);
+                ..set_active(was_selected);
                 ..add(&cascade! {
                     gtk::Label::new(Some(name.as_str()));
                     ..set_use_markup(true);
@@ -125,10 +153,19 @@ impl DevicesView {
                     (*view_ready.borrow())(nselected.get() != 0);
                 });
             };
+
+            if was_selected {
+                nselected.set(nselected.get() + 1);
+            }
+
             self.list.insert(&row, -1);
         }
 
+        *self.devices.borrow_mut() = devices.to_vec();
+
         self.list.show_all();
+
+        (*self.view_ready.borrow())(nselected.get() != 0);
     }
 
     pub fn reset(&self) {
@@ -139,4 +176,28 @@ impl DevicesView {
     pub fn connect_view_ready<F: Fn(bool) + 'static>(&self, func: F) {
         *self.view_ready.borrow_mut() = Box::new(func);
     }
+
+    /// Starts watching `/sys/class/block` for hotplug events and re-runs
+    /// `refresh` with a freshly fetched device list each time a drive
+    /// arrives or is removed, so the candidate list stays live instead of
+    /// only reflecting what was attached at launch.
+    ///
+    /// `fetch_devices` is called on every hotplug event to get the new
+    /// device list and image size; `refresh` is then invoked with that
+    /// result, preserving the existing selection as usual. The returned
+    /// watcher must be kept alive for as long as hotplug detection should
+    /// keep running.
+    pub fn watch_for_hotplug<F>(
+        self: &Rc<Self>,
+        mut fetch_devices: F,
+    ) -> notify::Result<RecommendedWatcher>
+    where
+        F: FnMut() -> (Vec<Arc<DiskDevice>>, u64) + 'static,
+    {
+        let view = self.clone();
+        watch_block_devices(move || {
+            let (devices, image_size) = fetch_devices();
+            view.refresh(&devices, image_size);
+        })
+    }
 }
@@ -0,0 +1,48 @@
+//! Watches `/sys/class/block` for drive arrival/removal so `DevicesView`
+//! can stay live instead of only snapshotting attached drives at launch.
+
+use glib;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{path::Path, sync::mpsc, thread, time::Duration};
+
+/// How long to wait for more sysfs events before treating a hotplug burst
+/// as settled; udev tends to fire several events per physical insertion.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Watches for block device hotplug and invokes `callback` on the GTK main
+/// loop each time a device arrives or is removed, coalescing a burst of
+/// sysfs events into a single call the same way `BlockWatcher` does on the
+/// CLI side.
+///
+/// The returned watcher must be kept alive for as long as the callback
+/// should keep firing; dropping it stops the underlying inotify instance.
+/// Events are relayed off the watcher's background thread onto the GTK
+/// main loop through a `glib::MainContext` channel, the usual pattern for
+/// letting a non-main thread poke the UI.
+pub fn watch_block_devices<F>(mut callback: F) -> notify::Result<RecommendedWatcher>
+where
+    F: FnMut() + 'static,
+{
+    let (tx, rx) = glib::MainContext::channel::<()>(glib::PRIORITY_DEFAULT);
+
+    rx.attach(None, move |_| {
+        callback();
+        glib::Continue(true)
+    });
+
+    let (raw_tx, raw_rx) = mpsc::channel();
+    let mut watcher: RecommendedWatcher = Watcher::new_immediate(move |_event| drop(raw_tx.send(())))?;
+
+    watcher.watch(Path::new("/sys/class/block"), RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        while raw_rx.recv().is_ok() {
+            while raw_rx.recv_timeout(DEBOUNCE).is_ok() {}
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(watcher)
+}